@@ -1,12 +1,14 @@
 use clap::Parser;
 use polars::prelude::*;
+use rayon::prelude::*;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::fs::File;
 
 /// Merge segmentations results from different runs, e.g. FOV
 /// into a single segmetation file. This is done by combining cells if the intersection over union
-/// is bigger than a set threshold. 
+/// is bigger than a set threshold.
 /// This approach is similar to the stichting that is done in cellpose.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None, verbatim_doc_comment)]
@@ -29,6 +31,72 @@ pub struct Args {
     /// Output file
     #[arg(long, default_value = "out.csv")]
     outfile: String,
+
+    /// How to reconcile column schemas across input files before concatenating them.
+    /// `strict` errors on any mismatch between files, `merge` takes the union of all columns,
+    /// filling absent ones with null and upcasting mismatched dtypes to a common one, and
+    /// `intersect` keeps only the columns present in every file.
+    #[arg(long, value_enum, default_value_t = SchemaMode::Strict)]
+    schema_mode: SchemaMode,
+
+    /// Number of threads to use when computing pairwise cell overlaps across layers.
+    /// Independent layer pairs share no state, so this is embarrassingly parallel; only the
+    /// final union-find step is serialized. Defaults to 1 (sequential).
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+
+    /// Split transcripts into a grid of tiles (by x/y coordinate, in the same units as the input
+    /// files) so that only one tile's worth of transcript-id joins is in memory at a time, instead
+    /// of every layer's full cell overlap table at once. A cell pair's shared-transcript count is
+    /// still accumulated across every tile before being scored, so splitting a cell's transcripts
+    /// across tile boundaries doesn't change the result. Not set by default; tiling is also
+    /// skipped automatically when the estimated working set is already small.
+    #[arg(long)]
+    tile_size: Option<f64>,
+
+    /// Estimated working-set size (bytes) below which `--tile-size` is ignored and all layers
+    /// are merged at once rather than tile-by-tile. Mainly useful for tests and for tuning the
+    /// cutoff on unusual hardware; the default matches the point where tiling's overhead roughly
+    /// pays for itself.
+    #[arg(long, default_value_t = SMALL_WORKING_SET_BYTES)]
+    min_tile_bytes: usize,
+
+    /// Write an auxiliary CSV documenting, for each final merged cell, every source
+    /// `(file, original_cell_id, score)` contribution (the score column is named after
+    /// `--metric`), and flagging matches that were ambiguous
+    /// (more than one candidate cleared the threshold before the reciprocal-best rank filter,
+    /// across every layer pair the cell was compared in, not just one).
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Similarity metric used to score cell overlaps. `iou` (intersection over union) is the
+    /// default; `containment` (intersection / min(tally_a, tally_b)) better reflects a real
+    /// correspondence when a fragment in one FOV matches a full cell in another; `dice`
+    /// (2 * intersection / (tally_a + tally_b)) is a softer middle ground between the two.
+    #[arg(long, value_enum, default_value_t = Metric::Iou)]
+    metric: Metric,
+}
+
+/// Which cell-overlap metric `score_candidates` scores candidate pairs with. `find_cells_to_merge`
+/// operates on the resulting score column unchanged, regardless of which metric produced it.
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
+pub enum Metric {
+    Iou,
+    Containment,
+    Dice,
+}
+
+/// Default for `--min-tile-bytes`: below this estimated working-set size, spatial tiling is
+/// skipped even if `--tile-size` was passed, since the overhead of tiling isn't worth it.
+const SMALL_WORKING_SET_BYTES: usize = 256 * 1024 * 1024;
+
+/// Controls how `reconcile_schema` lines up column sets and dtypes across input files that may
+/// come from different Baysor versions (extra columns, `qv` missing, `cell` as string vs int...).
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
+pub enum SchemaMode {
+    Strict,
+    Merge,
+    Intersect,
 }
 
 
@@ -42,9 +110,51 @@ impl fmt::Display for MyError {
 }
 impl Error for MyError {}
 
+/// Disjoint-set (union-find) over cell indices, with path compression and
+/// union-by-rank. Used to collapse transitive merge chains (A<->B, B<->C)
+/// into a single connected component in one order-independent pass, instead
+/// of relying on the order layers happen to be folded in.
+struct UnionFind {
+    parent: Vec<usize>,
+    rank: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(size: usize) -> Self {
+        UnionFind {
+            parent: (0..size).collect(),
+            rank: vec![0; size],
+        }
+    }
+
+    fn find(&mut self, i: usize) -> usize {
+        if self.parent[i] != i {
+            self.parent[i] = self.find(self.parent[i]);
+        }
+        self.parent[i]
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let ra = self.find(a);
+        let rb = self.find(b);
+        if ra == rb {
+            return;
+        }
+        if self.rank[ra] < self.rank[rb] {
+            self.parent[ra] = rb;
+        } else if self.rank[ra] > self.rank[rb] {
+            self.parent[rb] = ra;
+        } else {
+            self.parent[rb] = ra;
+            self.rank[ra] += 1;
+        }
+    }
+}
 
 /// The main logic of that program lies here.
-/// It reads in the files, reduces the list by merging the layers, and the combines it with the additional columns to create a output file.
+/// It reads in the files, finds the cells that should be merged across all of them using a
+/// union-find over cell ids, and then combines the result with the additional columns to create
+/// a output file.
 pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
     if args.files.len() < 2 {
         return Err(Box::new(MyError(
@@ -52,36 +162,121 @@ pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
         )));
     }
 
-    // Read in all the layers
-    // TODO: Improvement. Make sure that cell_id are unique. They should be Baysor created cell_ids based in the uuid of the process.
+    // Read in all the layers. The cell column is prefixed with a per-file token so that cell ids
+    // are unique across files, even if two Baysor runs happened to assign the same raw id. The
+    // x/y columns are only needed to cut the data into spatial tiles, so they are dropped again
+    // right away when tiling isn't requested.
     println!("Read files");
     let layers: Vec<LazyFrame> = args
         .files
         .iter()
-        .map(|v| {
-            LazyCsvReader::new(v)
-                .has_header(true)
-                .finish()
-                .unwrap()
-                .select(&[col("transcript_id"), col("cell")])
-        })
+        .enumerate()
+        .map(|(i, v)| read_layer(v, i, args.tile_size.is_some()))
         .collect();
+    let layers = reconcile_schema(&layers, &args.schema_mode)?;
 
-    // The main part of the script. Reduce the Vector by calling our merge function.
-    println!("Merging files");
-    let df_result = layers.into_iter().reduce(|a, b| merge(a, b, &args));
-    let df_result = match df_result {
-        Some(x) => x,
-        None => {
-            return Err(Box::new(MyError(
-                "Error occured while merging the layers".into(),
-            )))
+    // Compute the IOU for every overlapping cell pair, across every pair of layers, and turn the
+    // reciprocal-best pairs that clear the threshold into union-find edges.
+    println!("Computing cell overlaps");
+    let cells = collect_distinct_cells(&layers)?;
+    let cell_index: HashMap<String, usize> = cells
+        .iter()
+        .enumerate()
+        .map(|(i, cell)| (cell.clone(), i))
+        .collect();
+    let mut uf = UnionFind::new(cells.len());
+    let mut provenance = args.report.as_ref().map(|_| MergeProvenance::default());
+
+    // See `compute_cell_tally` for why this must be computed on the full, untiled layers.
+    let tally = compute_cell_tally(&layers)?;
+
+    match args.tile_size {
+        Some(tile_size) => {
+            let full_bytes = estimate_bytes(&layers)?;
+            if full_bytes < args.min_tile_bytes {
+                println!(
+                    "Estimated working set is {full_bytes} bytes (< {}); skipping spatial tiling",
+                    args.min_tile_bytes
+                );
+                union_all_pairs(
+                    &layers,
+                    &tally,
+                    args.threshold,
+                    args.threads,
+                    &args.metric,
+                    &cell_index,
+                    &mut uf,
+                    provenance.as_mut(),
+                )?;
+            } else {
+                let (min_x, max_x, min_y, max_y) = xy_bounds(&layers)?;
+                let tiles = build_tiles(min_x, max_x, min_y, max_y, tile_size);
+                println!(
+                    "Estimated working set is {full_bytes} bytes; splitting into {} tiles of size {tile_size}",
+                    tiles.len()
+                );
+                let mut peak_tile_bytes = 0usize;
+                for (i, tile) in tiles.iter().enumerate() {
+                    let tile_layers: Vec<LazyFrame> =
+                        layers.iter().map(|layer| layer_in_tile(layer, tile)).collect();
+                    let tile_bytes = estimate_bytes(&tile_layers)?;
+                    peak_tile_bytes = peak_tile_bytes.max(tile_bytes);
+                    println!("  tile {}/{}: {tile_bytes} bytes", i + 1, tiles.len());
+                }
+                println!(
+                    "Peak per-tile footprint was {peak_tile_bytes} bytes vs {full_bytes} bytes untiled"
+                );
+                union_tiled_pairs(
+                    &layers,
+                    &tiles,
+                    &tally,
+                    args.threshold,
+                    args.threads,
+                    &args.metric,
+                    &cell_index,
+                    &mut uf,
+                    provenance.as_mut(),
+                )?;
+            }
         }
-    };
+        None => union_all_pairs(
+            &layers,
+            &tally,
+            args.threshold,
+            args.threads,
+            &args.metric,
+            &cell_index,
+            &mut uf,
+            provenance.as_mut(),
+        )?,
+    }
+
+    // Map every cell to the representative (root) cell of its merged set.
+    let roots: Vec<&str> = (0..cells.len()).map(|i| cells[uf.find(i)].as_str()).collect();
+    if let (Some(report_path), Some(provenance)) = (&args.report, &provenance) {
+        write_report(report_path, &cells, &roots, &args.files, provenance, &args.metric)?;
+    }
+    let df_mapping =
+        DataFrame::new(vec![Series::new("cell", &cells), Series::new("root_cell", &roots)])?
+            .lazy();
+
+    // Resolve a single cell per transcript (the first layer it was seen with a cell in), then
+    // remap it through the union-find to its merged root.
+    println!("Merging files");
+    let df_cells = diag_concat_lf(layers, true, true)?
+        .filter(col("cell").is_not_null())
+        .unique(Some(vec!["transcript_id".to_string()]), UniqueKeepStrategy::First)
+        .left_join(df_mapping, col("cell"), col("cell"))
+        .select(&[col("transcript_id"), col("root_cell").alias("cell")]);
 
     // Create a list of all unique transcripts with the additional columns.
-    let df_all_transcripts = unique_transcripts(&args.files, &args.additional_columns)?.left_join(
-        df_result,
+    let df_all_transcripts = unique_transcripts(
+        &args.files,
+        &args.additional_columns,
+        &args.schema_mode,
+    )?
+    .left_join(
+        df_cells,
         col("transcript_id"),
         col("transcript_id"),
     );
@@ -97,102 +292,485 @@ pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-/// Merge two layers of transcripts
-/// For all cell pairs create a IOU. If a cell is new, keep it. If a cell has an IOU > threshold, merge it.
-fn merge(lhs: LazyFrame, rhs: LazyFrame, args: &Args) -> LazyFrame {
-    // Create a full table of all transcripts from the two layers: LHS and RHS
-    let df_join = lhs.outer_join(rhs, col("transcript_id"), col("transcript_id"));
+/// Read a single Baysor file, keeping only the columns needed for merging, and prefix its `cell`
+/// column with a per-file token so that cell ids are globally unique across all input files.
+/// `with_xy` also keeps the `x`/`y` coordinate columns, needed when `--tile-size` is set.
+fn read_layer(path: &str, token: usize, with_xy: bool) -> LazyFrame {
+    let mut columns = vec![col("transcript_id"), col("cell")];
+    if with_xy {
+        columns.push(col("x"));
+        columns.push(col("y"));
+    }
+    LazyCsvReader::new(path)
+        .has_header(true)
+        .finish()
+        .unwrap()
+        .select(&columns)
+        .with_columns([when(col("cell").is_not_null())
+            .then(lit(format!("{}_", token)) + col("cell").cast(DataType::Utf8))
+            .otherwise(lit(NULL))
+            .alias("cell")])
+}
 
-    // Create transcript counts per cell for both RHS and LHS cells
-    // This information will be used to calculate the IOU
-    let df_tally = df_join
-        .clone()
-        .groupby(["cell", "cell_right"])
-        .agg([col("transcript_id").count().alias("transcript_counts")]);
-    let df_tally_a = df_tally
-        .clone()
+/// One union-find edge: a reciprocal-best cell pair whose IOU cleared the threshold, along with
+/// the IOU itself so it can be recorded in the `--report` provenance output.
+struct MergeEdge {
+    cell: u32,
+    cell_right: u32,
+    iou: f32,
+}
+
+/// The outcome of comparing a single pair of layers.
+struct PairMergeResult {
+    edges: Vec<MergeEdge>,
+    /// One entry per cell appearance in this pair's candidates that cleared the IOU threshold,
+    /// before the reciprocal-best rank filter (a cell with two candidates here appears twice).
+    /// Accumulated across every layer pair in `MergeProvenance` so that a cell which clears
+    /// threshold with different partners in different layer pairs - not just within one pair -
+    /// is still recognized as ambiguous.
+    candidate_occurrences: Vec<u32>,
+}
+
+/// Accumulates `--report` provenance across every layer pair that was compared (with
+/// `--tile-size`, once per pair after its counts are summed over every tile): the best IOU each cell was matched with, and how many threshold-clearing
+/// candidates it had in total, used to derive which cells were ambiguous across the whole run.
+#[derive(Default)]
+struct MergeProvenance {
+    best_iou: HashMap<u32, f32>,
+    candidate_counts: HashMap<u32, u32>,
+}
+
+impl MergeProvenance {
+    fn record(&mut self, result: &PairMergeResult) {
+        for edge in &result.edges {
+            for cell in [edge.cell, edge.cell_right] {
+                let best = self.best_iou.entry(cell).or_insert(edge.iou);
+                if edge.iou > *best {
+                    *best = edge.iou;
+                }
+            }
+        }
+        for cell in &result.candidate_occurrences {
+            *self.candidate_counts.entry(*cell).or_insert(0) += 1;
+        }
+    }
+
+    /// Cells with more than one threshold-clearing candidate across the whole run, whether those
+    /// candidates came from the same layer pair or different ones (e.g. A matches both B in
+    /// pair(1,2) and C in pair(1,3)) - the many-to-many-across-FOVs case a global union-find
+    /// makes possible.
+    fn ambiguous_cells(&self) -> std::collections::HashSet<u32> {
+        self.candidate_counts
+            .iter()
+            .filter(|(_, count)| **count > 1)
+            .map(|(cell, _)| *cell)
+            .collect()
+    }
+}
+
+/// Run the pairwise IOU computation over every pair of layers and union the resulting edges into
+/// `uf`. Independent layer pairs share no state, so they're computed concurrently when
+/// `threads > 1`; only the union step and the provenance bookkeeping are serialized.
+///
+/// Used for the non-tiled path. See `union_tiled_pairs` for the tiled equivalent, and
+/// `compute_cell_tally` for why `tally` must be global and untiled either way.
+#[allow(clippy::too_many_arguments)]
+fn union_all_pairs(
+    layers: &[LazyFrame],
+    tally: &LazyFrame,
+    threshold: f32,
+    threads: usize,
+    metric: &Metric,
+    cell_index: &HashMap<String, usize>,
+    uf: &mut UnionFind,
+    mut provenance: Option<&mut MergeProvenance>,
+) -> Result<(), Box<dyn Error>> {
+    let pairs: Vec<(usize, usize)> = (0..layers.len())
+        .flat_map(|i| ((i + 1)..layers.len()).map(move |j| (i, j)))
+        .collect();
+    let compute_pair = |&(i, j): &(usize, usize)| -> PairMergeResult {
+        pair_edges(layers[i].clone(), layers[j].clone(), tally, threshold, metric, cell_index)
+            .unwrap()
+    };
+    let results: Vec<PairMergeResult> = if threads > 1 {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build()?;
+        pool.install(|| pairs.par_iter().map(compute_pair).collect())
+    } else {
+        pairs.iter().map(compute_pair).collect()
+    };
+    for result in &results {
+        for edge in &result.edges {
+            uf.union(edge.cell as usize, edge.cell_right as usize);
+        }
+        if let Some(provenance) = provenance.as_deref_mut() {
+            provenance.record(result);
+        }
+    }
+    Ok(())
+}
+
+/// Run the pairwise IOU computation over every pair of layers, tile by tile, but only decide
+/// each pair once: a cell pair's shared-transcript count (`transcript_counts`) is summed across
+/// every tile before being scored and thresholded, so a pair whose matching transcripts straddle
+/// a tile boundary isn't under-counted in every tile it's seen in. This relies on `tiles` being a
+/// non-overlapping partition (see `build_tiles`) - otherwise a boundary transcript would be
+/// double-counted.
+///
+/// Every (pair, tile) job is independent, so they're computed concurrently when `threads > 1`;
+/// summing the partial counts, scoring, and unioning are serialized.
+#[allow(clippy::too_many_arguments)]
+fn union_tiled_pairs(
+    layers: &[LazyFrame],
+    tiles: &[Tile],
+    tally: &LazyFrame,
+    threshold: f32,
+    threads: usize,
+    metric: &Metric,
+    cell_index: &HashMap<String, usize>,
+    uf: &mut UnionFind,
+    mut provenance: Option<&mut MergeProvenance>,
+) -> Result<(), Box<dyn Error>> {
+    let pairs: Vec<(usize, usize)> = (0..layers.len())
+        .flat_map(|i| ((i + 1)..layers.len()).map(move |j| (i, j)))
+        .collect();
+    let jobs: Vec<(usize, &Tile)> =
+        (0..pairs.len()).flat_map(|p| tiles.iter().map(move |tile| (p, tile))).collect();
+    let compute_job = |&(p, tile): &(usize, &Tile)| -> DataFrame {
+        let (i, j) = pairs[p];
+        pair_transcript_counts(layer_in_tile(&layers[i], tile), layer_in_tile(&layers[j], tile))
+            .collect()
+            .unwrap()
+    };
+    let job_results: Vec<DataFrame> = if threads > 1 {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build()?;
+        pool.install(|| jobs.par_iter().map(compute_job).collect())
+    } else {
+        jobs.iter().map(compute_job).collect()
+    };
+
+    // Sum each pair's shared-transcript count across every tile that saw it.
+    let mut totals: Vec<HashMap<(String, String), u32>> = vec![HashMap::new(); pairs.len()];
+    for ((p, _), df) in jobs.iter().zip(job_results.iter()) {
+        let cell = df.column("cell")?.utf8()?;
+        let cell_right = df.column("cell_right")?.utf8()?;
+        let counts = df.column("transcript_counts")?.u32()?;
+        for ((a, b), n) in cell
+            .into_no_null_iter()
+            .zip(cell_right.into_no_null_iter())
+            .zip(counts.into_no_null_iter())
+        {
+            *totals[*p].entry((a.to_string(), b.to_string())).or_insert(0) += n;
+        }
+    }
+
+    for counts in &totals {
+        if counts.is_empty() {
+            continue;
+        }
+        let df_iou = score_candidates(counts_to_lazyframe(counts)?, tally, metric);
+        let result = edges_from_scored(df_iou, threshold, cell_index)?;
+        for edge in &result.edges {
+            uf.union(edge.cell as usize, edge.cell_right as usize);
+        }
+        if let Some(provenance) = provenance.as_deref_mut() {
+            provenance.record(&result);
+        }
+    }
+    Ok(())
+}
+
+/// Turn a pair's per-tile transcript count totals into the single-row-per-candidate `LazyFrame`
+/// shape `score_candidates` expects, as if it had come straight out of `pair_transcript_counts`.
+fn counts_to_lazyframe(counts: &HashMap<(String, String), u32>) -> Result<LazyFrame, Box<dyn Error>> {
+    let cell: Vec<&str> = counts.keys().map(|(a, _)| a.as_str()).collect();
+    let cell_right: Vec<&str> = counts.keys().map(|(_, b)| b.as_str()).collect();
+    let transcript_counts: Vec<u32> = counts.values().copied().collect();
+    let df = DataFrame::new(vec![
+        Series::new("cell", cell),
+        Series::new("cell_right", cell_right),
+        Series::new("transcript_counts", transcript_counts),
+    ])?;
+    Ok(df.lazy())
+}
+
+/// A rectangular region of x/y space that transcripts are filtered into before being merged, so
+/// that only one tile's worth of data is in memory at a time.
+struct Tile {
+    x0: f64,
+    x1: f64,
+    y0: f64,
+    y1: f64,
+}
+
+/// Find the x/y bounding box across all layers, used to lay out the tile grid.
+fn xy_bounds(layers: &[LazyFrame]) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
+    let mut min_x = f64::INFINITY;
+    let mut max_x = f64::NEG_INFINITY;
+    let mut min_y = f64::INFINITY;
+    let mut max_y = f64::NEG_INFINITY;
+    for layer in layers {
+        let bounds = layer
+            .clone()
+            .select(&[
+                col("x").cast(DataType::Float64).min().alias("min_x"),
+                col("x").cast(DataType::Float64).max().alias("max_x"),
+                col("y").cast(DataType::Float64).min().alias("min_y"),
+                col("y").cast(DataType::Float64).max().alias("max_y"),
+            ])
+            .collect()?;
+        min_x = min_x.min(bounds.column("min_x")?.f64()?.get(0).unwrap_or(min_x));
+        max_x = max_x.max(bounds.column("max_x")?.f64()?.get(0).unwrap_or(max_x));
+        min_y = min_y.min(bounds.column("min_y")?.f64()?.get(0).unwrap_or(min_y));
+        max_y = max_y.max(bounds.column("max_y")?.f64()?.get(0).unwrap_or(max_y));
+    }
+    Ok((min_x, max_x, min_y, max_y))
+}
+
+/// Lay a grid of tiles over the bounding box. Tiles form a strict, non-overlapping partition -
+/// `union_tiled_pairs` sums each pair's shared-transcript count across every tile it's seen in, so
+/// a transcript counted twice (had tiles overlapped) would inflate that sum.
+///
+/// The loop bounds are nudged past `max_x`/`max_y` by a hair so that a transcript sitting exactly
+/// on the far edge (e.g. `max_x` landing on an exact multiple of `tile_size`) still gets its own
+/// tile, rather than falling just outside every half-open `[x0, x1)` tile and being silently
+/// dropped from every pair's intersection count.
+fn build_tiles(min_x: f64, max_x: f64, min_y: f64, max_y: f64, tile_size: f64) -> Vec<Tile> {
+    let eps = tile_size * 1e-9;
+    let mut tiles = Vec::new();
+    let mut x = min_x;
+    while x < max_x + eps {
+        let mut y = min_y;
+        while y < max_y + eps {
+            tiles.push(Tile { x0: x, x1: x + tile_size, y0: y, y1: y + tile_size });
+            y += tile_size;
+        }
+        x += tile_size;
+    }
+    tiles
+}
+
+/// Restrict a layer to the transcripts that fall inside `tile`.
+fn layer_in_tile(layer: &LazyFrame, tile: &Tile) -> LazyFrame {
+    let x = col("x").cast(DataType::Float64);
+    let y = col("y").cast(DataType::Float64);
+    layer.clone().filter(
+        x.clone()
+            .gt_eq(lit(tile.x0))
+            .and(x.lt(lit(tile.x1)))
+            .and(y.clone().gt_eq(lit(tile.y0)))
+            .and(y.lt(lit(tile.y1))),
+    )
+}
+
+/// Rough per-value byte cost for a dtype, used by `estimate_bytes` to size a layer without
+/// collecting it. Fixed-width types use their actual width; `Utf8` has no fixed width, so we
+/// assume a generous average short-string size (e.g. a gene name or quoted cell id).
+fn estimate_dtype_bytes(dtype: &DataType) -> usize {
+    match dtype {
+        DataType::Boolean | DataType::Int8 | DataType::UInt8 => 1,
+        DataType::Int16 | DataType::UInt16 => 2,
+        DataType::Int32 | DataType::UInt32 | DataType::Float32 => 4,
+        DataType::Int64 | DataType::UInt64 | DataType::Float64 => 8,
+        DataType::Utf8 => 32,
+        _ => 8,
+    }
+}
+
+/// Approximate the in-memory footprint of a set of layers, in bytes. Used to log the before/after
+/// footprint of tiling, and to decide whether tiling is worth it at all for a given run.
+///
+/// This must stay cheap: it's called once to decide whether to tile at all, and again per tile
+/// once tiling kicks in, so it must not materialize the layers it's sizing (the whole point of
+/// tiling is to avoid holding the full working set in memory at once). Instead it reads the
+/// schema for a per-row byte estimate and gets the row count via a single-column `count()`
+/// aggregation, which pushes projection down to just that column rather than reading every row
+/// of every column.
+fn estimate_bytes(layers: &[LazyFrame]) -> Result<usize, Box<dyn Error>> {
+    let mut total = 0usize;
+    for layer in layers {
+        let schema = layer.schema()?;
+        let row_bytes: usize = schema.iter_dtypes().map(estimate_dtype_bytes).sum();
+        let first_col = schema
+            .iter_names()
+            .next()
+            .ok_or_else(|| MyError("Layer has no columns".into()))?
+            .clone();
+        let row_count = layer
+            .clone()
+            .select(&[col(&first_col).count()])
+            .collect()?
+            .column(&first_col)?
+            .u32()?
+            .get(0)
+            .unwrap_or(0) as usize;
+        total += row_count * row_bytes;
+    }
+    Ok(total)
+}
+
+/// Compute each cell's total transcript count across the full, untiled set of layers. This is
+/// the denominator `score_candidates` joins against - it must always be computed on the untiled
+/// layers, never tile-restricted data, so a cell whose transcripts are split across several tiles
+/// still gets scored against its true size instead of just the slice one tile happens to see.
+fn compute_cell_tally(layers: &[LazyFrame]) -> Result<LazyFrame, Box<dyn Error>> {
+    let tally = diag_concat_lf(layers.to_vec(), true, true)?
+        .filter(col("cell").is_not_null())
         .groupby(["cell"])
-        .agg([col("transcript_counts").sum().alias("tally_a")]);
-    let df_tally_b = df_tally
-        .clone()
-        .groupby(["cell_right"])
-        .agg([col("transcript_counts").sum().alias("tally_b")]);
-
-    // IOU = (transcripts in A and B) / (transcrips in A + transcripts in B - transcripts in A and B)
-    // where A is the cell on the LHS and B is the cell on the RHS.
-    let df_iou = df_tally
-        // join the total counts per cell for each of the two tables
-        .left_join(df_tally_a, col("cell"), col("cell"))
-        .left_join(df_tally_b, col("cell_right"), col("cell_right"))
-        // calcuate the IOU as in cellpose
-        .with_columns([(col("transcript_counts").cast(DataType::Float32)
-            / (col("tally_a") + col("tally_b")
-                - col("transcript_counts").cast(DataType::Float32)))
-        .alias("iou")]);
-
-    // Find cells that are new. They sould have the biggest IOU with the null cell id on the lhs
-    let df_new_cells = find_new_cells(df_iou.clone());
-
-    // Find cells that should be merged.
-    let df_merge_cells = find_cells_to_merge(df_iou, args.threshold);
-
-    // Create the final results table.
-    let df_result = df_join
-        .left_join(
-            df_new_cells
-                .clone()
-                .select(&[col("cell_right"), col("rank_cell_right").alias("is_new")]),
-            col("cell_right"),
-            col("cell_right"),
-        )
-        .left_join(
-            df_merge_cells
-                .clone()
-                .select(&[col("cell_right"), col("cell").alias("merged_cell_id")]),
-            col("cell_right"),
-            col("cell_right"),
-        )
-        .with_columns([when(col("merged_cell_id").is_not_null())
-            .then(col("merged_cell_id"))
-            .otherwise(
-                when(col("is_new").eq(1))
-                    .then(col("cell_right"))
-                    .otherwise(col("cell")),
-            )
-            .alias("new_cell")])
-        .select(&[col("transcript_id"), col("new_cell").alias("cell")]);
-    println!(".. working");
-    // get the results for the first merge.. then move on to the next round...
-    let df_result = df_result.collect().unwrap();
-    df_result.lazy()
-}
-
-/// Takes a Lazy DataFrame and finds cells, that are only in the rhs but not in the lhs.
-/// For this, cell_right is filtered on non-null cells, that ranked by IOU.
-/// If a cell is not present on the lhs, the hightest IOU should be with cell "null" on the lhs
-fn find_new_cells(df_iou: LazyFrame) -> LazyFrame {
-    df_iou
+        .agg([col("transcript_id").count().alias("tally")])
+        .collect()?;
+    Ok(tally.lazy())
+}
+
+/// Collect every distinct (already globally-unique) cell id across all layers.
+fn collect_distinct_cells(layers: &[LazyFrame]) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut cells: Vec<String> = Vec::new();
+    for layer in layers {
+        let df = layer
+            .clone()
+            .select(&[col("cell")])
+            .filter(col("cell").is_not_null())
+            .unique(None, UniqueKeepStrategy::First)
+            .collect()?;
+        for value in df.column("cell")?.utf8()?.into_no_null_iter() {
+            cells.push(value.to_string());
+        }
+    }
+    cells.sort_unstable();
+    cells.dedup();
+    Ok(cells)
+}
+
+/// Score each candidate pair's overlap according to `metric`, given the shared transcript count
+/// and each side's total tally. Kept separate so `score_candidates` can stay metric-agnostic.
+fn metric_score(metric: &Metric) -> Expr {
+    let intersection = col("transcript_counts").cast(DataType::Float32);
+    let tally_a = col("tally_a").cast(DataType::Float32);
+    let tally_b = col("tally_b").cast(DataType::Float32);
+    match metric {
+        // IOU = (transcripts in A and B) / (transcripts in A + transcripts in B - transcripts in A and B)
+        Metric::Iou => intersection.clone() / (tally_a + tally_b - intersection),
+        // Containment = intersection / min(tally_a, tally_b), which better reflects a real
+        // correspondence when a fragment in one FOV matches a full cell in another.
+        Metric::Containment => {
+            let smaller = when(tally_a.clone().lt(tally_b.clone()))
+                .then(tally_a)
+                .otherwise(tally_b);
+            intersection / smaller
+        }
+        // Dice = 2 * intersection / (tally_a + tally_b)
+        Metric::Dice => (intersection * lit(2.0)) / (tally_a + tally_b),
+    }
+}
+
+/// The `--report` CSV's score column is named after whichever metric actually produced it, so a
+/// run with `--metric containment` doesn't get a column mislabelled `iou`.
+fn metric_column_name(metric: &Metric) -> &'static str {
+    match metric {
+        Metric::Iou => "iou",
+        Metric::Containment => "containment",
+        Metric::Dice => "dice",
+    }
+}
+
+/// Count shared transcripts per candidate cell pair for a single pair of (possibly
+/// tile-restricted) layers - the numerator of the overlap score. `union_tiled_pairs` sums this
+/// across every tile a pair appears in before it's scored, so it's fine for this to only see one
+/// tile's worth of data at a time.
+fn pair_transcript_counts(lhs: LazyFrame, rhs: LazyFrame) -> LazyFrame {
+    let rhs = rhs.select(&[col("transcript_id"), col("cell").alias("cell_right")]);
+    lhs.outer_join(rhs, col("transcript_id"), col("transcript_id"))
+        .filter(col("cell").is_not_null())
         .filter(col("cell_right").is_not_null())
-        .select(&[col("cell"), col("cell_right"), col("iou")])
-        // Create a rank for hightst matching cell for both cells (left and right)
-        .with_columns([col("iou")
-            .rank(
-                RankOptions {
-                    method: RankMethod::Max,
-                    descending: true,
-                },
-                Some(0),
-            )
-            .over(&[col("cell_right")])
-            .alias("rank_cell_right")])
-        .filter(col("rank_cell_right").eq(1).and(col("cell").is_null()))
+        .groupby(["cell", "cell_right"])
+        .agg([col("transcript_id").count().alias("transcript_counts")])
 }
 
-/// Find cells to merge
-/// Empty cells on lhs and rhs are removed
-/// Ranks are created for the best match for both cells on the lhs and rhs.
-/// if the rank==1 for both sides, the cells will be merged
-fn find_cells_to_merge(df_iou: LazyFrame, threshold: f32) -> LazyFrame {
+/// Join a table of per-pair shared-transcript counts against `tally` and score each candidate by
+/// `metric`. See `compute_cell_tally` for why `tally` must be the global, untiled per-cell count.
+fn score_candidates(counts: LazyFrame, tally: &LazyFrame, metric: &Metric) -> LazyFrame {
+    let tally_a = tally.clone().select(&[col("cell"), col("tally").alias("tally_a")]);
+    let tally_b =
+        tally.clone().select(&[col("cell").alias("cell_right"), col("tally").alias("tally_b")]);
+    counts
+        .left_join(tally_a, col("cell"), col("cell"))
+        .left_join(tally_b, col("cell_right"), col("cell_right"))
+        .with_columns([metric_score(metric).alias("iou")])
+}
+
+/// Compute a cell x cell overlap table for a single pair of layers, scored by `metric`. Used for
+/// the non-tiled path; see `union_tiled_pairs` for how tiled runs accumulate `transcript_counts`
+/// across tiles before scoring instead.
+fn pair_iou(lhs: LazyFrame, rhs: LazyFrame, tally: &LazyFrame, metric: &Metric) -> LazyFrame {
+    score_candidates(pair_transcript_counts(lhs, rhs), tally, metric)
+}
+
+/// Find the union-find edges for a single pair of layers: every reciprocal-best cell pair whose
+/// score clears the threshold becomes one `(u32, u32)` cell-index edge, alongside every cell's
+/// raw candidate occurrences in this pair, for `MergeProvenance` to accumulate into a global
+/// ambiguity count across every layer pair compared.
+fn pair_edges(
+    lhs: LazyFrame,
+    rhs: LazyFrame,
+    tally: &LazyFrame,
+    threshold: f32,
+    metric: &Metric,
+    cell_index: &HashMap<String, usize>,
+) -> Result<PairMergeResult, Box<dyn Error>> {
+    edges_from_scored(pair_iou(lhs, rhs, tally, metric), threshold, cell_index)
+}
+
+/// Shared by the non-tiled and tiled paths: turn an already-scored candidate table into
+/// union-find edges (reciprocal-best pairs clearing `threshold`) and raw candidate occurrences
+/// (for `MergeProvenance`'s ambiguity count).
+fn edges_from_scored(
+    df_iou: LazyFrame,
+    threshold: f32,
+    cell_index: &HashMap<String, usize>,
+) -> Result<PairMergeResult, Box<dyn Error>> {
+    let df_candidates = threshold_candidates(df_iou.clone(), threshold).collect()?;
+    let candidate_occurrences = candidate_cell_occurrences(&df_candidates, cell_index)?;
+
+    let df_merge = find_cells_to_merge(df_iou, threshold).collect()?;
+    let cell = df_merge.column("cell")?.utf8()?;
+    let cell_right = df_merge.column("cell_right")?.utf8()?;
+    let iou = df_merge.column("iou")?.f32()?;
+
+    let mut edges = Vec::with_capacity(df_merge.height());
+    for ((a, b), value) in cell
+        .into_no_null_iter()
+        .zip(cell_right.into_no_null_iter())
+        .zip(iou.into_no_null_iter())
+    {
+        edges.push(MergeEdge {
+            cell: cell_index[a] as u32,
+            cell_right: cell_index[b] as u32,
+            iou: value,
+        });
+    }
+    Ok(PairMergeResult { edges, candidate_occurrences })
+}
+
+/// One cell-index entry per candidate appearance in `df_candidates` (a cell with two candidates
+/// in this pair appears twice). `MergeProvenance` sums these across every layer pair compared, so
+/// that ambiguity reflects the whole run rather than just this one pair - a cell that clears
+/// threshold once here and once in a different layer pair is still counted as ambiguous.
+fn candidate_cell_occurrences(
+    df_candidates: &DataFrame,
+    cell_index: &HashMap<String, usize>,
+) -> Result<Vec<u32>, Box<dyn Error>> {
+    let mut occurrences = Vec::new();
+    for value in df_candidates.column("cell")?.utf8()?.into_no_null_iter() {
+        occurrences.push(cell_index[value] as u32);
+    }
+    for value in df_candidates.column("cell_right")?.utf8()?.into_no_null_iter() {
+        occurrences.push(cell_index[value] as u32);
+    }
+    Ok(occurrences)
+}
+
+/// Remove empty cells and subset candidate cell pairs to those clearing the IOU threshold.
+/// Shared by `find_cells_to_merge` and the ambiguity check in `edges_from_scored`.
+fn threshold_candidates(df_iou: LazyFrame, threshold: f32) -> LazyFrame {
     df_iou
         // Remove null cells, these we not be merged anyway
         .filter(col("cell").is_not_null())
@@ -200,6 +778,13 @@ fn find_cells_to_merge(df_iou: LazyFrame, threshold: f32) -> LazyFrame {
         // Subset by threshold
         .filter(col("iou").gt_eq(threshold))
         .select(&[col("cell"), col("cell_right"), col("iou")])
+}
+
+/// Find cells to merge
+/// Ranks are created for the best match for both cells on the lhs and rhs.
+/// if the rank==1 for both sides, the cells will be merged
+fn find_cells_to_merge(df_iou: LazyFrame, threshold: f32) -> LazyFrame {
+    threshold_candidates(df_iou, threshold)
         // Create a rank for hightst matching cell for both cells (left and right)
         .with_columns([
             col("iou")
@@ -228,24 +813,238 @@ fn find_cells_to_merge(df_iou: LazyFrame, threshold: f32) -> LazyFrame {
         .filter(col("rank_cell_right").eq(1))
 }
 
+/// Write the `--report` CSV: one row per source cell, documenting which merged cell it ended up
+/// in, the file and original (un-prefixed) cell id it came from, the best score (named after
+/// whichever `--metric` produced it) it was matched with, and whether that match was ambiguous.
+fn write_report(
+    path: &str,
+    cells: &[String],
+    roots: &[&str],
+    files: &[String],
+    provenance: &MergeProvenance,
+    metric: &Metric,
+) -> Result<(), Box<dyn Error>> {
+    let mut merged_cell_id = Vec::with_capacity(cells.len());
+    let mut source_file = Vec::with_capacity(cells.len());
+    let mut original_cell_id = Vec::with_capacity(cells.len());
+    let mut score: Vec<Option<f32>> = Vec::with_capacity(cells.len());
+    let mut ambiguous = Vec::with_capacity(cells.len());
+    let ambiguous_cells = provenance.ambiguous_cells();
+
+    for (idx, cell) in cells.iter().enumerate() {
+        let (token, raw_id) = cell
+            .split_once('_')
+            .ok_or_else(|| MyError(format!("Cell id \"{cell}\" is missing the file-token prefix")))?;
+        let token: usize = token
+            .parse()
+            .map_err(|_| MyError(format!("Cell id \"{cell}\" has a non-numeric file token")))?;
+
+        merged_cell_id.push(roots[idx].to_string());
+        source_file.push(files[token].clone());
+        original_cell_id.push(raw_id.to_string());
+        // No entry means this cell was never part of a reciprocal-best pair clearing
+        // `--threshold` (e.g. a singleton cell unique to one FOV, or a cell whose best candidates
+        // tied); leave it null rather than implying a perfect match.
+        score.push(provenance.best_iou.get(&(idx as u32)).copied());
+        ambiguous.push(ambiguous_cells.contains(&(idx as u32)));
+    }
+
+    let mut df = DataFrame::new(vec![
+        Series::new("merged_cell_id", merged_cell_id),
+        Series::new("source_file", source_file),
+        Series::new("original_cell_id", original_cell_id),
+        Series::new(metric_column_name(metric), score),
+        Series::new("ambiguous", ambiguous),
+    ])?;
+
+    println!("Saving merge provenance report to \"{path}\"");
+    let mut output_file = File::create(path)?;
+    CsvWriter::new(&mut output_file).finish(&mut df)?;
+    Ok(())
+}
+
 /// Build a list of unique transcripts from all passed subsets
 /// Include additional columns
-fn unique_transcripts(files: &Vec<String>, columns: &Vec<String>) -> Result<LazyFrame, Box<dyn Error>> {
+fn unique_transcripts(
+    files: &Vec<String>,
+    columns: &Vec<String>,
+    schema_mode: &SchemaMode,
+) -> Result<LazyFrame, Box<dyn Error>> {
     let result: Vec<LazyFrame> = files
         .iter()
         .map(|v| {
-            LazyCsvReader::new(v)
-                .has_header(true)
-                .finish()
-                .unwrap()
-                .select(&[
-                    col("transcript_id"),
-                    cols(columns),
-                ])
+            let frame = LazyCsvReader::new(v).has_header(true).finish()?;
+            // Only select the additional columns this file actually has; a file missing e.g.
+            // `qv` must not error out here, since `--schema-mode merge` is meant to paper over
+            // exactly that. Schema reconciliation (filling nulls, erroring, intersecting) is
+            // `reconcile_schema`'s job, not this one's.
+            let schema = frame.schema()?;
+            let present: Vec<Expr> = columns
+                .iter()
+                .filter(|name| schema.get(name.as_str()).is_some())
+                .map(|name| col(name))
+                .collect();
+            let mut select_exprs = vec![col("transcript_id")];
+            select_exprs.extend(present);
+            Ok(frame.select(&select_exprs))
         })
-        .collect();
+        .collect::<PolarsResult<Vec<LazyFrame>>>()?;
+    let result = reconcile_schema(&result, schema_mode)?;
 
     let df_concat = diag_concat_lf(result, true, true)?.unique(None, UniqueKeepStrategy::First);
 
     Ok(df_concat)
 }
+
+/// Whether `dtype` is one of the fixed-width integer types polars infers from a CSV.
+fn is_integral(dtype: &DataType) -> bool {
+    matches!(
+        dtype,
+        DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::UInt8
+            | DataType::UInt16
+            | DataType::UInt32
+            | DataType::UInt64
+    )
+}
+
+/// Promote two dtypes to a common one that both can be safely cast to: matching types are kept
+/// as-is, anything paired with `Utf8` becomes `Utf8` (e.g. a quoted cell id vs a plain int), two
+/// different integer widths (e.g. one file's `transcript_id` inferring as `Int32` and another's
+/// as `Int64`) are promoted to `Int64` rather than a float - `transcript_id` is used as an exact
+/// join key throughout `run()`, and a `Float64` cast risks both precision loss and silently wrong
+/// joins for large ids - and any other mismatch (e.g. `i32` vs `f64`) is promoted to `Float64`.
+fn promote_dtype(a: &DataType, b: &DataType) -> DataType {
+    if a == b {
+        a.clone()
+    } else if matches!(a, DataType::Utf8) || matches!(b, DataType::Utf8) {
+        DataType::Utf8
+    } else if is_integral(a) && is_integral(b) {
+        DataType::Int64
+    } else {
+        DataType::Float64
+    }
+}
+
+/// Reconcile the schemas of several frames before they are concatenated with `diag_concat_lf`,
+/// according to `args.schema_mode`. Files from different Baysor versions often differ (extra
+/// columns, `qv` missing, `cell` stored as string vs int); this resolves those differences
+/// upfront instead of letting the concat fail or silently produce nulls in unexpected places.
+fn reconcile_schema(
+    frames: &[LazyFrame],
+    schema_mode: &SchemaMode,
+) -> Result<Vec<LazyFrame>, Box<dyn Error>> {
+    let schemas: Vec<Schema> = frames
+        .iter()
+        .map(|frame| frame.schema().map(|schema| (*schema).clone()))
+        .collect::<PolarsResult<Vec<_>>>()?;
+
+    match schema_mode {
+        SchemaMode::Strict => {
+            if schemas.iter().any(|schema| schema != &schemas[0]) {
+                return Err(Box::new(MyError(
+                    "Input files have mismatched schemas. Use --schema-mode merge or intersect, or fix the inputs.".into(),
+                )));
+            }
+            Ok(frames.to_vec())
+        }
+        SchemaMode::Intersect => {
+            let mut common: Vec<String> = schemas[0]
+                .iter_names()
+                .map(|name| name.to_string())
+                .collect();
+            for schema in &schemas[1..] {
+                common.retain(|name| schema.get(name).is_some());
+            }
+
+            let mut dtypes: HashMap<String, DataType> = HashMap::new();
+            for name in &common {
+                let mut dtype = schemas[0].get(name).unwrap().clone();
+                for schema in &schemas[1..] {
+                    dtype = promote_dtype(&dtype, schema.get(name).unwrap());
+                }
+                dtypes.insert(name.clone(), dtype);
+            }
+
+            Ok(frames
+                .iter()
+                .map(|frame| {
+                    let exprs: Vec<Expr> = common
+                        .iter()
+                        .map(|name| col(name).cast(dtypes[name].clone()))
+                        .collect();
+                    frame.clone().select(&exprs)
+                })
+                .collect())
+        }
+        SchemaMode::Merge => {
+            let mut columns: Vec<String> = Vec::new();
+            for schema in &schemas {
+                for name in schema.iter_names() {
+                    let name = name.to_string();
+                    if !columns.contains(&name) {
+                        columns.push(name);
+                    }
+                }
+            }
+
+            let mut dtypes: HashMap<String, DataType> = HashMap::new();
+            for name in &columns {
+                let mut dtype: Option<DataType> = None;
+                for schema in &schemas {
+                    if let Some(found) = schema.get(name) {
+                        dtype = Some(match dtype {
+                            Some(existing) => promote_dtype(&existing, found),
+                            None => found.clone(),
+                        });
+                    }
+                }
+                dtypes.insert(name.clone(), dtype.unwrap());
+            }
+
+            let mut out = Vec::with_capacity(frames.len());
+            for (frame, schema) in frames.iter().zip(schemas.iter()) {
+                let exprs: Vec<Expr> = columns
+                    .iter()
+                    .map(|name| {
+                        let dtype = dtypes[name].clone();
+                        if schema.get(name).is_some() {
+                            col(name).cast(dtype).alias(name)
+                        } else {
+                            lit(NULL).cast(dtype).alias(name)
+                        }
+                    })
+                    .collect();
+                out.push(frame.clone().select(&exprs));
+            }
+            Ok(out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod promote_dtype_tests {
+    use super::*;
+
+    #[test]
+    fn integer_mismatches_promote_to_a_wider_integer() {
+        // transcript_id is used as an exact-match join key throughout run(); promoting it to
+        // Float64 here would risk precision loss and subtly wrong joins for large ids.
+        assert_eq!(promote_dtype(&DataType::Int32, &DataType::Int64), DataType::Int64);
+        assert_eq!(promote_dtype(&DataType::UInt8, &DataType::Int64), DataType::Int64);
+    }
+
+    #[test]
+    fn non_integer_mismatches_still_promote_to_float() {
+        assert_eq!(promote_dtype(&DataType::Int32, &DataType::Float64), DataType::Float64);
+    }
+
+    #[test]
+    fn utf8_takes_priority_over_any_numeric_type() {
+        assert_eq!(promote_dtype(&DataType::Int64, &DataType::Utf8), DataType::Utf8);
+        assert_eq!(promote_dtype(&DataType::Utf8, &DataType::Float64), DataType::Utf8);
+    }
+}
@@ -0,0 +1,88 @@
+use clap::Parser;
+use merge_baysor::{run, Args};
+use std::collections::HashMap;
+use std::fs;
+
+/// Three files whose cells all fully overlap each other pairwise: A (file1) with B (file2), A
+/// with C (file3), and B with C. Within any *single* layer pair, each cell only ever has one
+/// threshold-clearing candidate - it's only across the three pairs together that every cell turns
+/// out to have two. This is the many-to-many-across-FOVs scenario the all-pairs union-find makes
+/// possible, and `--report`'s `ambiguous` column is supposed to flag it.
+fn write_fixture(path: &str, cell: &str, transcript_ids: &[&str]) {
+    let mut content = String::from("transcript_id,cell,x,y,z,qv,overlaps_nucleus,gene\n");
+    for transcript_id in transcript_ids {
+        content.push_str(&format!("{transcript_id},{cell},1,1,0,30,1,GeneA\n"));
+    }
+    fs::write(path, content).unwrap();
+}
+
+struct ReportRow {
+    source_file: String,
+    original_cell_id: String,
+    ambiguous: String,
+}
+
+fn read_report(path: &str) -> Vec<ReportRow> {
+    let content = fs::read_to_string(path).unwrap();
+    let mut lines = content.lines();
+    let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+    let idx = |name: &str| header.iter().position(|h| *h == name).unwrap();
+    let (file_col, id_col, ambiguous_col) =
+        (idx("source_file"), idx("original_cell_id"), idx("ambiguous"));
+    lines
+        .map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            ReportRow {
+                source_file: fields[file_col].to_string(),
+                original_cell_id: fields[id_col].to_string(),
+                ambiguous: fields[ambiguous_col].to_string(),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn ambiguity_is_tracked_across_layer_pairs_not_just_within_one() {
+    let dir = std::env::temp_dir().join(format!("merge-baysor-report-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let file1 = dir.join("file1.csv");
+    let file2 = dir.join("file2.csv");
+    let file3 = dir.join("file3.csv");
+    write_fixture(file1.to_str().unwrap(), "A", &["1", "2", "3"]);
+    write_fixture(file2.to_str().unwrap(), "B", &["1", "2", "3"]);
+    write_fixture(file3.to_str().unwrap(), "C", &["1", "2", "3"]);
+
+    let outfile = dir.join("out.csv");
+    let report = dir.join("report.csv");
+
+    let args = Args::parse_from([
+        "merge-baysor",
+        file1.to_str().unwrap(),
+        file2.to_str().unwrap(),
+        file3.to_str().unwrap(),
+        "--outfile",
+        outfile.to_str().unwrap(),
+        "--report",
+        report.to_str().unwrap(),
+        "--threshold",
+        "0.5",
+    ]);
+    run(args).unwrap();
+
+    let rows = read_report(report.to_str().unwrap());
+    let by_original_id: HashMap<&str, &ReportRow> =
+        rows.iter().map(|r| (r.original_cell_id.as_str(), r)).collect();
+
+    for (file, cell) in [(&file1, "A"), (&file2, "B"), (&file3, "C")] {
+        let row = by_original_id[cell];
+        assert_eq!(row.source_file, file.to_str().unwrap());
+        assert_eq!(
+            row.ambiguous, "true",
+            "cell {cell} clears threshold with a different partner in each of two layer pairs, \
+             so it must be flagged ambiguous even though neither pair alone is ambiguous"
+        );
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,71 @@
+use clap::Parser;
+use merge_baysor::{run, Args};
+use std::fs;
+
+/// Cell A (file1) has 10 transcripts, 5 of which are also cell B (file2)'s entire set - a
+/// fragment of A fully contained in B's data. IOU = 5/10 = 0.5, containment = 5/min(10,5) = 1.0,
+/// dice = 10/15 ≈ 0.67: the three metrics disagree enough to tell them apart, and the `--report`
+/// column must be named after whichever one actually produced the score.
+fn write_fixture(path: &str, cell: &str, transcript_ids: &[i32]) {
+    let mut content = String::from("transcript_id,cell,x,y,z,qv,overlaps_nucleus,gene\n");
+    for transcript_id in transcript_ids {
+        content.push_str(&format!("{transcript_id},{cell},1,1,0,30,1,GeneA\n"));
+    }
+    fs::write(path, content).unwrap();
+}
+
+fn report_header(path: &str) -> Vec<String> {
+    fs::read_to_string(path).unwrap().lines().next().unwrap().split(',').map(String::from).collect()
+}
+
+#[test]
+fn report_score_column_is_named_after_the_active_metric() {
+    let dir = std::env::temp_dir().join(format!("merge-baysor-metric-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let file1 = dir.join("file1.csv");
+    let file2 = dir.join("file2.csv");
+    write_fixture(file1.to_str().unwrap(), "A", &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+    write_fixture(file2.to_str().unwrap(), "B", &[1, 2, 3, 4, 5]);
+
+    let run_with_metric = |metric: &str, threshold: &str, report: &std::path::Path, outfile: &std::path::Path| {
+        let args = Args::parse_from([
+            "merge-baysor",
+            file1.to_str().unwrap(),
+            file2.to_str().unwrap(),
+            "--outfile",
+            outfile.to_str().unwrap(),
+            "--report",
+            report.to_str().unwrap(),
+            "--metric",
+            metric,
+            "--threshold",
+            threshold,
+        ]);
+        run(args).unwrap();
+    };
+
+    let containment_report = dir.join("containment_report.csv");
+    run_with_metric(
+        "containment",
+        "0.9",
+        &containment_report,
+        &dir.join("containment_out.csv"),
+    );
+    let header = report_header(containment_report.to_str().unwrap());
+    assert!(header.contains(&"containment".to_string()));
+    assert!(!header.contains(&"iou".to_string()));
+
+    let dice_report = dir.join("dice_report.csv");
+    run_with_metric("dice", "0.6", &dice_report, &dir.join("dice_out.csv"));
+    let header = report_header(dice_report.to_str().unwrap());
+    assert!(header.contains(&"dice".to_string()));
+    assert!(!header.contains(&"iou".to_string()));
+
+    let iou_report = dir.join("iou_report.csv");
+    run_with_metric("iou", "0.1", &iou_report, &dir.join("iou_out.csv"));
+    let header = report_header(iou_report.to_str().unwrap());
+    assert!(header.contains(&"iou".to_string()));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
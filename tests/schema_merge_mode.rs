@@ -0,0 +1,66 @@
+use clap::Parser;
+use merge_baysor::{run, Args};
+use std::fs;
+
+/// Two files with mismatched schemas, the case `--schema-mode merge` is meant to paper over:
+/// file2 is missing the `qv` column entirely; every other column is written the same way in both
+/// files.
+fn write_file_with_qv(path: &str, rows: &[(&str, &str)]) {
+    let mut content = String::from("transcript_id,cell,x,y,z,qv,overlaps_nucleus,gene\n");
+    for (transcript_id, cell) in rows {
+        content.push_str(&format!("{transcript_id},{cell},1,1,0,30,1,GeneA\n"));
+    }
+    fs::write(path, content).unwrap();
+}
+
+fn write_file_without_qv(path: &str, rows: &[(&str, &str)]) {
+    let mut content = String::from("transcript_id,cell,x,y,z,overlaps_nucleus,gene\n");
+    for (transcript_id, cell) in rows {
+        content.push_str(&format!("{transcript_id},{cell},1,1,0,1,GeneA\n"));
+    }
+    fs::write(path, content).unwrap();
+}
+
+#[test]
+fn schema_mode_merge_tolerates_a_missing_column() {
+    let dir = std::env::temp_dir().join(format!("merge-baysor-schema-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let file1 = dir.join("file1.csv");
+    let file2 = dir.join("file2.csv");
+    write_file_with_qv(file1.to_str().unwrap(), &[("1", "A"), ("2", "A")]);
+    write_file_without_qv(file2.to_str().unwrap(), &[("3", "B"), ("4", "B")]);
+
+    let outfile = dir.join("out.csv");
+    let args = Args::parse_from([
+        "merge-baysor",
+        file1.to_str().unwrap(),
+        file2.to_str().unwrap(),
+        "--outfile",
+        outfile.to_str().unwrap(),
+        "--schema-mode",
+        "merge",
+    ]);
+    run(args).unwrap();
+
+    let out = fs::read_to_string(outfile.to_str().unwrap()).unwrap();
+    let mut lines = out.lines();
+    let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+    assert!(header.contains(&"qv"), "qv column must survive schema-merge even though file2 lacks it");
+
+    let qv_col = header.iter().position(|h| *h == "qv").unwrap();
+    let transcript_col = header.iter().position(|h| *h == "transcript_id").unwrap();
+    let mut qv_by_transcript = std::collections::HashMap::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(',').collect();
+        qv_by_transcript.insert(fields[transcript_col].to_string(), fields[qv_col].to_string());
+    }
+
+    assert_eq!(qv_by_transcript["1"], "30");
+    assert!(
+        qv_by_transcript["3"].is_empty(),
+        "transcripts from the file missing qv must come through with a null, not an error"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,79 @@
+use clap::Parser;
+use merge_baysor::{run, Args};
+use std::fs;
+
+/// A tiny three-file dataset with transcripts shared across overlapping FOVs, so that both of
+/// the two independent cell-pairs ((file1, file2) and (file2, file3)) produce a union-find edge,
+/// while the third pair (file1, file3) does not. Exercises the parallel fold across more than one
+/// pair, not just the trivial single-pair case.
+fn write_fixture(path: &str, rows: &[(&str, &str, f64, f64)]) {
+    let mut content = String::from("transcript_id,cell,x,y,z,qv,overlaps_nucleus,gene\n");
+    for (transcript_id, cell, x, y) in rows {
+        content.push_str(&format!("{transcript_id},{cell},{x},{y},0,30,1,GeneA\n"));
+    }
+    fs::write(path, content).unwrap();
+}
+
+fn read_sorted_lines(path: &str) -> Vec<String> {
+    let mut lines: Vec<String> =
+        fs::read_to_string(path).unwrap().lines().map(|s| s.to_string()).collect();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn single_threaded_and_multi_threaded_paths_agree() {
+    let dir = std::env::temp_dir().join(format!("merge-baysor-threads-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let file1 = dir.join("file1.csv");
+    let file2 = dir.join("file2.csv");
+    let file3 = dir.join("file3.csv");
+    write_fixture(
+        file1.to_str().unwrap(),
+        &[("1", "A", 1.0, 1.0), ("2", "A", 1.1, 1.0), ("3", "A", 1.2, 1.0), ("4", "B", 5.0, 5.0)],
+    );
+    write_fixture(
+        file2.to_str().unwrap(),
+        &[("1", "A", 1.0, 1.0), ("2", "A", 1.1, 1.0), ("3", "A", 1.2, 1.0), ("6", "D", 8.0, 8.0), ("7", "D", 8.1, 8.0)],
+    );
+    write_fixture(
+        file3.to_str().unwrap(),
+        &[("6", "D", 8.0, 8.0), ("7", "D", 8.1, 8.0), ("9", "E", 20.0, 20.0)],
+    );
+
+    let out_single = dir.join("out_single.csv");
+    let out_multi = dir.join("out_multi.csv");
+
+    let args_single = Args::parse_from([
+        "merge-baysor",
+        file1.to_str().unwrap(),
+        file2.to_str().unwrap(),
+        file3.to_str().unwrap(),
+        "--outfile",
+        out_single.to_str().unwrap(),
+        "--threads",
+        "1",
+    ]);
+    run(args_single).unwrap();
+
+    let args_multi = Args::parse_from([
+        "merge-baysor",
+        file1.to_str().unwrap(),
+        file2.to_str().unwrap(),
+        file3.to_str().unwrap(),
+        "--outfile",
+        out_multi.to_str().unwrap(),
+        "--threads",
+        "4",
+    ]);
+    run(args_multi).unwrap();
+
+    assert_eq!(
+        read_sorted_lines(out_single.to_str().unwrap()),
+        read_sorted_lines(out_multi.to_str().unwrap()),
+        "single-threaded and multi-threaded merges must produce identical output"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,167 @@
+use clap::Parser;
+use merge_baysor::{run, Args};
+use std::collections::HashMap;
+use std::fs;
+
+/// Two files where cell "A" (file1) fully overlaps the *first six* of cell "B" (file2)'s nine
+/// transcripts, but "B" also has transcripts of its own far enough away to land in a different
+/// spatial tile. Cell A's true global tally is 6, cell B's is 9, so the correct IOU is 6/9 ≈ 0.67
+/// — below the 0.7 threshold used below, so A and B must NOT merge. A tiling implementation that
+/// computes `tally_b` from only the tile holding the overlap would see `tally_b` == 6 there,
+/// inflating the score to 1.0 and merging them — exactly the regression this test guards against.
+fn write_fixture(path: &str, rows: &[(&str, &str, f64, f64)]) {
+    let mut content = String::from("transcript_id,cell,x,y,z,qv,overlaps_nucleus,gene\n");
+    for (transcript_id, cell, x, y) in rows {
+        content.push_str(&format!("{transcript_id},{cell},{x},{y},0,30,1,GeneA\n"));
+    }
+    fs::write(path, content).unwrap();
+}
+
+fn read_cell_by_transcript(path: &str) -> HashMap<String, String> {
+    let content = fs::read_to_string(path).unwrap();
+    let mut lines = content.lines();
+    let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+    let transcript_col = header.iter().position(|h| *h == "transcript_id").unwrap();
+    let cell_col = header.iter().position(|h| *h == "cell").unwrap();
+    lines
+        .map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            (fields[transcript_col].to_string(), fields[cell_col].to_string())
+        })
+        .collect()
+}
+
+#[test]
+fn tiled_and_untiled_runs_agree_for_a_cell_spanning_tile_boundary() {
+    let dir = std::env::temp_dir().join(format!("merge-baysor-tiling-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let file1 = dir.join("file1.csv");
+    let file2 = dir.join("file2.csv");
+    write_fixture(
+        file1.to_str().unwrap(),
+        &[
+            ("1", "A", 1.0, 1.0),
+            ("2", "A", 2.0, 1.0),
+            ("3", "A", 3.0, 1.0),
+            ("4", "A", 4.0, 1.0),
+            ("5", "A", 5.0, 1.0),
+            ("6", "A", 6.0, 1.0),
+        ],
+    );
+    write_fixture(
+        file2.to_str().unwrap(),
+        &[
+            ("1", "B", 1.0, 1.0),
+            ("2", "B", 2.0, 1.0),
+            ("3", "B", 3.0, 1.0),
+            ("4", "B", 4.0, 1.0),
+            ("5", "B", 5.0, 1.0),
+            ("6", "B", 6.0, 1.0),
+            ("7", "B", 15.0, 2.0),
+            ("8", "B", 16.0, 2.0),
+            ("9", "B", 17.0, 2.0),
+        ],
+    );
+
+    let out_tiled = dir.join("out_tiled.csv");
+    let out_untiled = dir.join("out_untiled.csv");
+
+    // `--min-tile-bytes 0` forces the tiled code path even for this tiny fixture; tile-size 10
+    // puts transcripts 1-6 and 7-9 in different, non-overlapping tiles.
+    let args_tiled = Args::parse_from([
+        "merge-baysor",
+        file1.to_str().unwrap(),
+        file2.to_str().unwrap(),
+        "--outfile",
+        out_tiled.to_str().unwrap(),
+        "--threshold",
+        "0.7",
+        "--tile-size",
+        "10",
+        "--min-tile-bytes",
+        "0",
+    ]);
+    run(args_tiled).unwrap();
+
+    let args_untiled = Args::parse_from([
+        "merge-baysor",
+        file1.to_str().unwrap(),
+        file2.to_str().unwrap(),
+        "--outfile",
+        out_untiled.to_str().unwrap(),
+        "--threshold",
+        "0.7",
+    ]);
+    run(args_untiled).unwrap();
+
+    let tiled = read_cell_by_transcript(out_tiled.to_str().unwrap());
+    let untiled = read_cell_by_transcript(out_untiled.to_str().unwrap());
+
+    assert_eq!(tiled, untiled, "tiled and untiled runs must assign the same merged cell ids");
+    assert_ne!(
+        tiled["1"], tiled["7"],
+        "A's true overlap with B is 6/9 ≈ 0.67, below --threshold 0.7, so they must not merge \
+         even when A and B's overlapping transcripts fall in one tile and B's extra transcripts \
+         fall in another"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Cell "A" (file1) and cell "B" (file2) share all 6 of their transcripts, split evenly across two
+/// non-overlapping tiles: 3 land in the first tile, 3 in the second. Each tile on its own only
+/// ever sees 3 of the 6 shared transcripts, so a tile-local intersection count scores each tile at
+/// 3/(6+6-3) ≈ 0.33 — below the 0.9 threshold below in every tile individually. Only summing the
+/// intersection across both tiles before scoring gives the true global IOU of 6/6 = 1.0, which
+/// clears the threshold. A tiling implementation that decides per tile instead of accumulating
+/// across tiles first would never merge A and B, unlike an untiled run.
+#[test]
+fn a_cell_pairs_shared_transcripts_split_across_tiles_still_clear_threshold() {
+    let dir =
+        std::env::temp_dir().join(format!("merge-baysor-tiling-split-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let file1 = dir.join("file1.csv");
+    let file2 = dir.join("file2.csv");
+    let rows = |cell: &str| -> Vec<(&str, &str, f64, f64)> {
+        vec![
+            ("1", cell, 1.0, 1.0),
+            ("2", cell, 2.0, 1.0),
+            ("3", cell, 3.0, 1.0),
+            ("4", cell, 11.0, 1.0),
+            ("5", cell, 12.0, 1.0),
+            ("6", cell, 13.0, 1.0),
+        ]
+    };
+    write_fixture(file1.to_str().unwrap(), &rows("A"));
+    write_fixture(file2.to_str().unwrap(), &rows("B"));
+
+    let outfile = dir.join("out.csv");
+    // tile-size 10 with the grid starting at the smallest x (1) splits x in [1, 11) from x in
+    // [11, 21), putting transcripts 1-3 and 4-6 in different, non-overlapping tiles.
+    let args = Args::parse_from([
+        "merge-baysor",
+        file1.to_str().unwrap(),
+        file2.to_str().unwrap(),
+        "--outfile",
+        outfile.to_str().unwrap(),
+        "--threshold",
+        "0.9",
+        "--tile-size",
+        "10",
+        "--min-tile-bytes",
+        "0",
+    ]);
+    run(args).unwrap();
+
+    let cell_by_transcript = read_cell_by_transcript(outfile.to_str().unwrap());
+    assert_eq!(
+        cell_by_transcript["1"], cell_by_transcript["4"],
+        "A and B's shared transcripts are split evenly across two tiles, so neither tile alone \
+         sees enough overlap to clear --threshold, but their true global IOU is 1.0 and they must \
+         still merge"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,65 @@
+use clap::Parser;
+use merge_baysor::{run, Args};
+use std::fs;
+
+/// Three files forming a transitive chain: cell A (file1) overlaps cell B (file2), and B overlaps
+/// cell C (file3), but A and C share no transcripts at all. A pairwise-sequential reduction that
+/// folds layers in file order could miss collapsing A and C into the same group depending on fold
+/// order; the all-pairs union-find redesign is supposed to collapse A-B-C into one component
+/// regardless of which pair happens to be compared first.
+fn write_fixture(path: &str, cell: &str, transcript_ids: &[&str]) {
+    let mut content = String::from("transcript_id,cell,x,y,z,qv,overlaps_nucleus,gene\n");
+    for transcript_id in transcript_ids {
+        content.push_str(&format!("{transcript_id},{cell},1,1,0,30,1,GeneA\n"));
+    }
+    fs::write(path, content).unwrap();
+}
+
+#[test]
+fn a_three_way_transitive_chain_collapses_into_one_cell() {
+    let dir = std::env::temp_dir().join(format!("merge-baysor-transitive-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let file1 = dir.join("file1.csv");
+    let file2 = dir.join("file2.csv");
+    let file3 = dir.join("file3.csv");
+    // A <-> B share 1,2,3; B <-> C share 4,5,6; A and C share nothing.
+    write_fixture(file1.to_str().unwrap(), "A", &["1", "2", "3"]);
+    write_fixture(file2.to_str().unwrap(), "B", &["1", "2", "3", "4", "5", "6"]);
+    write_fixture(file3.to_str().unwrap(), "C", &["4", "5", "6"]);
+
+    let outfile = dir.join("out.csv");
+    let args = Args::parse_from([
+        "merge-baysor",
+        file1.to_str().unwrap(),
+        file2.to_str().unwrap(),
+        file3.to_str().unwrap(),
+        "--outfile",
+        outfile.to_str().unwrap(),
+        "--threshold",
+        "0.4",
+    ]);
+    run(args).unwrap();
+
+    let out = fs::read_to_string(outfile.to_str().unwrap()).unwrap();
+    let mut lines = out.lines();
+    let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+    let transcript_col = header.iter().position(|h| *h == "transcript_id").unwrap();
+    let cell_col = header.iter().position(|h| *h == "cell").unwrap();
+
+    let mut cell_by_transcript = std::collections::HashMap::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(',').collect();
+        cell_by_transcript.insert(fields[transcript_col].to_string(), fields[cell_col].to_string());
+    }
+
+    let cell_for_a = &cell_by_transcript["1"];
+    let cell_for_c = &cell_by_transcript["4"];
+    assert_eq!(
+        cell_for_a, cell_for_c,
+        "A and C share no transcripts directly, but both overlap B, so all three must end up \
+         in the same merged cell via transitive closure, not just the two directly-overlapping pairs"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}